@@ -1,16 +1,24 @@
 use std::collections::{HashMap, VecDeque};
-use std::sync::{Arc, Mutex};
-use std::time::{self, Duration};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{self, Duration, SystemTime};
 
 use crate::code_blocks::{BlockId, BlockStore};
 use crate::utils::InsertPush as _;
 
-use itertools::Itertools;
+use hdrhistogram::Histogram;
 use wasmer::WasmerEnv;
 
+/// Cap on how many recent (duration, timestamp) pairs are retained per block
+/// for Influx line protocol export. Keeps memory bounded while still giving
+/// a time-series sink per-execution granularity over the last window of
+/// activity, rather than collapsing a whole run into a single aggregate point.
+const RECENT_SAMPLES_CAP: usize = 4096;
+
 #[derive(Default, Debug, Clone, WasmerEnv)]
 pub struct Measurements {
-    measurements: Vec<Measurement>,
+    blocks: Arc<RwLock<HashMap<BlockId, Arc<BlockStats>>>>,
+    track_recent: bool,
 }
 
 impl Measurements {
@@ -18,68 +26,232 @@ impl Measurements {
         Self::default()
     }
 
-    /// Returns an execution number used to identify this measurement. The Wasm code
-    /// will later supply this same identifier via `take_measurement`.
-    pub fn start_measurement(&mut self) -> u32 {
-        self.measurements.push(Measurement::new());
-        self.measurements.len() as u32 - 1
+    /// Enables retaining a bounded window of recent (duration, timestamp)
+    /// samples per block, which `Results::write_influx_line_protocol` needs
+    /// and nothing else does. Off by default: tracking it costs a
+    /// `SystemTime::now()` call and ring-buffer bookkeeping on every
+    /// `take_measurement`, inside the same lock as the Welford/histogram
+    /// update, so callers who don't need Influx export shouldn't pay for it.
+    pub fn with_influx_export(mut self) -> Self {
+        self.track_recent = true;
+        self
+    }
+
+    /// Returns a lightweight token carrying the start instant. The Wasm code
+    /// will later hand this same token back via `take_measurement`.
+    pub fn start_measurement(&self) -> MeasurementToken {
+        MeasurementToken {
+            start: time::Instant::now(),
+        }
     }
 
     // TODO: Error handling? This will be called from Wasm code probably.
-    pub fn take_measurement(&mut self, execution: u32, block_id: impl Into<BlockId>) {
-        self.measurements[execution as usize].take(block_id);
+    //
+    // Needs only `&self`, so Wasm instances on different threads can record
+    // concurrently without serializing through one collector-wide lock.
+    // Note this removes *cross-block* contention, not *same-block*
+    // contention: two threads racing to record the same `block_id` still
+    // serialize briefly on that block's own lock (see `BlockStats`), since
+    // its running mean/variance/histogram/recent-samples window can only be
+    // updated one sample at a time. Min/max for a block are lock-free even
+    // under same-block contention.
+    pub fn take_measurement(&self, token: MeasurementToken, block_id: impl Into<BlockId>) {
+        let elapsed = token.start.elapsed();
+        let block_id = block_id.into();
+
+        // The read lock is only ever contended with the rare write lock taken
+        // below to register a block seen for the first time; once a block's
+        // `BlockStats` exists, recording against it takes only that block's
+        // own lock (plus lock-free min/max updates).
+        let stats = {
+            let blocks = self.blocks.read().unwrap();
+            blocks.get(&block_id).cloned()
+        };
+        let stats = stats.unwrap_or_else(|| {
+            self.blocks
+                .write()
+                .unwrap()
+                .entry(block_id)
+                .or_insert_with(|| Arc::new(BlockStats::new()))
+                .clone()
+        });
+
+        stats.record(elapsed.as_nanos() as u64, self.track_recent);
     }
 
-    pub fn compile_results(&mut self) -> Results {
+    pub fn compile_results(&self) -> Results {
+        let blocks = self.blocks.read().unwrap();
         Results {
-            data: self
-                .measurements
-                .drain(..)
-                .filter_map(|ms| match ms {
-                    Measurement::Started(_) => {
-                        eprintln!("warning: a measurement was started, but not finalized");
-                        None
-                    }
-                    Measurement::Taken(block_id, duration) => Some((block_id, duration)),
-                })
-                .into_group_map(),
+            data: blocks
+                .iter()
+                .map(|(block_id, stats)| (*block_id, stats.snapshot()))
+                .collect(),
         }
     }
 
-    pub fn clear(&mut self) {
-        self.measurements = Vec::new();
+    pub fn clear(&self) {
+        self.blocks.write().unwrap().clear();
     }
 }
 
-#[derive(Debug, Clone)]
-enum Measurement {
-    Started(time::Instant),
-    Taken(BlockId, time::Duration),
+/// A handle to an in-flight measurement. Carries only the start instant, so
+/// handing it back to `take_measurement` from any thread needs no shared
+/// mutable state until the very end, when the elapsed time is folded into the
+/// target block's stats.
+#[derive(Debug, Clone, Copy)]
+pub struct MeasurementToken {
+    start: time::Instant,
 }
 
-impl Measurement {
-    pub fn new() -> Self {
-        Self::Started(time::Instant::now())
+/// Per-block accumulator updated concurrently from however many threads are
+/// executing that block's Wasm at once. `min_nanos`/`max_nanos` are folded in
+/// with a compare-and-swap loop and need no lock, so recording a measurement
+/// for block A never waits on block B, and never waits on another thread
+/// recording block A's min/max either.
+///
+/// `mean`/`m2` (Welford's algorithm) and the quantile histogram can't be
+/// updated that way: each new sample's contribution depends on the current
+/// sample count and mean, so the three have to advance together. Those live
+/// behind one lock scoped to a single block, which still avoids serializing
+/// all blocks behind one collector-wide lock even though it doesn't make
+/// *every* field lock-free.
+#[derive(Debug)]
+struct BlockStats {
+    min_nanos: AtomicU64,
+    max_nanos: AtomicU64,
+    moments: Mutex<Moments>,
+}
+
+/// Running mean and sum-of-squared-deviations (Welford's online algorithm),
+/// updated one sample at a time so memory stays O(1) regardless of how many
+/// executions a block racks up.
+#[derive(Debug)]
+struct Moments {
+    count: u64,
+    sum_nanos: u64,
+    mean: f64,
+    m2: f64,
+    histogram: Histogram<u64>,
+    /// Bounded ring buffer of the most recent samples, so Influx export can
+    /// still stream one point per execution rather than per block.
+    recent: VecDeque<(u64, SystemTime)>,
+}
+
+impl BlockStats {
+    fn new() -> Self {
+        Self {
+            min_nanos: AtomicU64::new(u64::MAX),
+            max_nanos: AtomicU64::new(0),
+            moments: Mutex::new(Moments {
+                count: 0,
+                sum_nanos: 0,
+                mean: 0.0,
+                m2: 0.0,
+                histogram: Histogram::new(3).unwrap(),
+                recent: VecDeque::with_capacity(RECENT_SAMPLES_CAP),
+            }),
+        }
     }
 
-    pub fn take(&mut self, block_id: impl Into<BlockId>) {
-        match self {
-            Measurement::Started(start) => *self = Self::Taken(block_id.into(), start.elapsed()),
-            Measurement::Taken(_, _) => {
-                panic!("attempt to take a measurement that was already taken")
+    fn record(&self, nanos: u64, track_recent: bool) {
+        let mut min = self.min_nanos.load(Ordering::Relaxed);
+        while nanos < min {
+            match self.min_nanos.compare_exchange_weak(
+                min,
+                nanos,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(observed) => min = observed,
+            }
+        }
+
+        let mut max = self.max_nanos.load(Ordering::Relaxed);
+        while nanos > max {
+            match self.max_nanos.compare_exchange_weak(
+                max,
+                nanos,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(observed) => max = observed,
+            }
+        }
+
+        let mut moments = self.moments.lock().unwrap();
+        let x = nanos as f64;
+        moments.count += 1;
+        moments.sum_nanos += nanos;
+        let delta = x - moments.mean;
+        moments.mean += delta / moments.count as f64;
+        let delta2 = x - moments.mean;
+        moments.m2 += delta * delta2;
+        moments.histogram.record(nanos).unwrap();
+
+        if track_recent {
+            moments.recent.push_back((nanos, SystemTime::now()));
+            if moments.recent.len() > RECENT_SAMPLES_CAP {
+                moments.recent.pop_front();
             }
         }
     }
+
+    fn snapshot(&self) -> BlockSnapshot {
+        let mut moments = self.moments.lock().unwrap();
+        let variance = if moments.count > 0 {
+            moments.m2 / moments.count as f64
+        } else {
+            0.0
+        };
+
+        BlockSnapshot {
+            count: moments.count,
+            sum_nanos: moments.sum_nanos,
+            mean: moments.mean,
+            stddev: variance.sqrt(),
+            min_nanos: self.min_nanos.load(Ordering::Relaxed),
+            max_nanos: self.max_nanos.load(Ordering::Relaxed),
+            histogram: moments.histogram.clone(),
+            // Drained, not cloned: each sample must be exported exactly once.
+            // Cloning here would re-emit every sample still in the window on
+            // every export call. Draining does not prevent loss: if a block
+            // runs more than `RECENT_SAMPLES_CAP` times between two calls to
+            // `compile_results`, the oldest samples roll off the front before
+            // being drained and are never exported.
+            recent: std::mem::take(&mut moments.recent),
+        }
+    }
 }
 
-impl WasmerEnv for Measurement {}
+/// A point-in-time read of a block's accumulated stats, taken once by
+/// `compile_results` so the rest of the reporting pipeline works with a
+/// stable, non-atomic value.
+#[derive(Clone, Debug)]
+pub struct BlockSnapshot {
+    pub count: u64,
+    pub sum_nanos: u64,
+    pub mean: f64,
+    pub stddev: f64,
+    pub min_nanos: u64,
+    pub max_nanos: u64,
+    histogram: Histogram<u64>,
+    recent: VecDeque<(u64, SystemTime)>,
+}
 
 #[derive(Clone, Debug)]
 pub struct Results {
-    data: HashMap<BlockId, Vec<time::Duration>>,
+    data: HashMap<BlockId, BlockSnapshot>,
 }
 
 impl Results {
+    /// Returns the aggregated `count`/`sum`/`mean`/`stddev`/`min`/`max` for a
+    /// block, or `None` if no measurements were ever taken for it.
+    pub fn stats(&self, block_id: impl Into<BlockId>) -> Option<&BlockSnapshot> {
+        self.data.get(&block_id.into())
+    }
+
     pub fn compile_csv(&self, block_store: Arc<Mutex<BlockStore>>, sink: impl std::io::Write) {
         let block_store = block_store.lock().unwrap();
         let mut wtr = csv::WriterBuilder::new()
@@ -88,31 +260,128 @@ impl Results {
             .from_writer(sink);
 
         // Header row
-        wtr.write_record(["block", "executions", "avg in ns", "min in ns", "max in ns"])
-            .unwrap();
+        wtr.write_record([
+            "block",
+            "executions",
+            "avg in ns",
+            "stddev in ns",
+            "min in ns",
+            "max in ns",
+            "p50 in ns",
+            "p90 in ns",
+            "p99 in ns",
+            "p999 in ns",
+        ])
+        .unwrap();
 
-        for (block_id, timings) in &self.data {
-            let avg = timings.iter().sum::<Duration>().as_nanos() / timings.len() as u128;
-            let min = timings.iter().min().unwrap().as_nanos();
-            let max = timings.iter().max().unwrap().as_nanos();
-            let executions = timings.len();
+        for (block_id, snapshot) in &self.data {
+            let p50 = snapshot.histogram.value_at_quantile(0.50);
+            let p90 = snapshot.histogram.value_at_quantile(0.90);
+            let p99 = snapshot.histogram.value_at_quantile(0.99);
+            let p999 = snapshot.histogram.value_at_quantile(0.999);
 
             let block = format!("{:?}", block_store.get_block(*block_id).unwrap());
             wtr.write_record([
                 block,
-                executions.to_string(),
-                avg.to_string(),
-                min.to_string(),
-                max.to_string(),
+                snapshot.count.to_string(),
+                snapshot.mean.to_string(),
+                snapshot.stddev.to_string(),
+                snapshot.min_nanos.to_string(),
+                snapshot.max_nanos.to_string(),
+                p50.to_string(),
+                p90.to_string(),
+                p99.to_string(),
+                p999.to_string(),
             ])
             .unwrap();
-
-            // wtr.write_record(timings.iter().map(|d| d.as_nanos().to_string()))
-            //     .unwrap();
         }
 
         wtr.flush().unwrap();
     }
+
+    /// Emits one InfluxDB line protocol line per sample:
+    /// `block_exec,block_id=<id> duration_ns=<n>i <unix_nanos_timestamp>`
+    ///
+    /// Each block only retains its last `RECENT_SAMPLES_CAP` samples (see
+    /// `Moments::recent`), so this streams true per-execution points — with
+    /// real per-sample timestamps — over a bounded recent window rather than
+    /// the whole history of a long-running block.
+    pub fn write_influx_line_protocol(&self, mut sink: impl std::io::Write) {
+        for (block_id, snapshot) in &self.data {
+            for (nanos, taken_at) in &snapshot.recent {
+                let timestamp = taken_at
+                    .duration_since(time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_nanos();
+
+                writeln!(
+                    sink,
+                    "block_exec,block_id={} duration_ns={}i {}",
+                    block_id.0, nanos, timestamp,
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    /// Renders the per-block timings as a Prometheus/OpenMetrics summary, so
+    /// the profiler can be scraped by standard monitoring stacks without any
+    /// CSV post-processing.
+    pub fn write_prometheus(
+        &self,
+        block_store: Arc<Mutex<BlockStore>>,
+        mut sink: impl std::io::Write,
+    ) {
+        let block_store = block_store.lock().unwrap();
+
+        writeln!(sink, "# TYPE cosmwasm_block_exec_duration_ns summary").unwrap();
+
+        for (block_id, snapshot) in &self.data {
+            let block = escape_prometheus_label(&format!(
+                "{:?}",
+                block_store.get_block(*block_id).unwrap()
+            ));
+
+            for (quantile, value) in [
+                (0.5, snapshot.histogram.value_at_quantile(0.50)),
+                (0.9, snapshot.histogram.value_at_quantile(0.90)),
+                (0.99, snapshot.histogram.value_at_quantile(0.99)),
+                (0.999, snapshot.histogram.value_at_quantile(0.999)),
+            ] {
+                writeln!(
+                    sink,
+                    "cosmwasm_block_exec_duration_ns{{block_id=\"{}\",quantile=\"{}\"}} {}",
+                    block, quantile, value,
+                )
+                .unwrap();
+            }
+
+            writeln!(
+                sink,
+                "cosmwasm_block_exec_duration_ns_sum{{block_id=\"{}\"}} {}",
+                block, snapshot.sum_nanos,
+            )
+            .unwrap();
+            writeln!(
+                sink,
+                "cosmwasm_block_exec_duration_ns_count{{block_id=\"{}\"}} {}",
+                block, snapshot.count,
+            )
+            .unwrap();
+        }
+    }
+}
+
+/// Escapes a Prometheus/OpenMetrics text exposition label value: backslashes,
+/// double quotes, and newlines must be backslash-escaped, or the line is
+/// invalid and breaks scraping. Unlike `compile_csv`, which gets CSV quoting
+/// for free from the `csv` crate, this format is hand-built, so it's escaped
+/// by hand here too.
+fn escape_prometheus_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
 }
 
 #[cfg(test)]
@@ -121,24 +390,162 @@ mod tests {
 
     #[test]
     fn take_measurements_of_different_blocks() {
-        // TODO: This is probably very confusing. What's a good way to refactor?
-
-        let mut measure = Measurements::new();
+        let measure = Measurements::new();
 
         let ms0 = measure.start_measurement();
         let ms1 = measure.start_measurement();
         std::thread::sleep(time::Duration::from_millis(100));
         let ms2 = measure.start_measurement();
-        let _ms3 = measure.start_measurement();
 
         measure.take_measurement(ms0, 0);
         measure.take_measurement(ms1, 1);
         measure.take_measurement(ms2, 0);
 
-        assert_eq!(measure.measurements.len(), 4);
+        let results = measure.compile_results();
+
+        assert_eq!(results.data[&BlockId(0)].count, 2);
+        assert!(results.data[&BlockId(0)].max_nanos > Duration::from_millis(100).as_nanos() as u64);
+    }
+
+    #[test]
+    fn quantiles_reflect_a_skewed_distribution() {
+        let stats = BlockStats::new();
+
+        // 99 fast executions, one 50x-slower outlier: p50 should sit near the
+        // bulk of the fast executions while p999 captures the tail spike.
+        for _ in 0..99 {
+            stats.record(1_000, false);
+        }
+        stats.record(50_000, false);
+
+        let snapshot = stats.snapshot();
+
+        let p50 = snapshot.histogram.value_at_quantile(0.50);
+        let p90 = snapshot.histogram.value_at_quantile(0.90);
+        let p99 = snapshot.histogram.value_at_quantile(0.99);
+        let p999 = snapshot.histogram.value_at_quantile(0.999);
+
+        assert!(p50 <= p90);
+        assert!(p90 <= p99);
+        assert!(p99 <= p999);
+        assert!(p999 >= 50_000 / 10); // far above the 1_000 bulk, so the spike is captured
+    }
+
+    #[test]
+    fn influx_line_protocol_emits_one_line_per_recorded_sample() {
+        let stats = BlockStats::new();
+        stats.record(1_000, true);
+        stats.record(2_000, true);
+        stats.record(3_000, true);
+
+        let results = Results {
+            data: HashMap::from([(BlockId(0), stats.snapshot())]),
+        };
+
+        let mut sink = Vec::new();
+        results.write_influx_line_protocol(&mut sink);
+        let output = String::from_utf8(sink).unwrap();
+        let lines: Vec<_> = output.lines().collect();
+
+        assert_eq!(lines.len(), 3);
+        for (line, nanos) in lines.iter().zip([1_000, 2_000, 3_000]) {
+            let expected_prefix = format!("block_exec,block_id=0 duration_ns={}i ", nanos);
+            assert!(
+                line.starts_with(&expected_prefix),
+                "line {:?} did not start with {:?}",
+                line,
+                expected_prefix
+            );
+        }
+    }
+
+    #[test]
+    fn influx_export_tracking_is_opt_in() {
+        let measure = Measurements::new();
+        let token = measure.start_measurement();
+        measure.take_measurement(token, 0);
+
+        let mut sink = Vec::new();
+        measure
+            .compile_results()
+            .write_influx_line_protocol(&mut sink);
+        assert!(
+            sink.is_empty(),
+            "recent samples shouldn't be tracked by default"
+        );
+
+        let measure = Measurements::new().with_influx_export();
+        let token = measure.start_measurement();
+        measure.take_measurement(token, 0);
+
+        let mut sink = Vec::new();
+        measure
+            .compile_results()
+            .write_influx_line_protocol(&mut sink);
+        assert_eq!(String::from_utf8(sink).unwrap().lines().count(), 1);
+    }
+
+    #[test]
+    fn concurrent_take_measurement_drops_nothing_and_converges_min_max() {
+        const THREADS: usize = 8;
+        const PER_THREAD: usize = 200;
+
+        let measure = Measurements::new();
+
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let measure = measure.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..PER_THREAD {
+                        let token = measure.start_measurement();
+                        measure.take_measurement(token, 0);
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
 
         let results = measure.compile_results();
+        let snapshot = results.stats(0).unwrap();
 
-        assert!(results.data[&BlockId(0)][0] > time::Duration::from_millis(100));
+        // No increments lost to the race between first-writer block creation
+        // and concurrent recording.
+        assert_eq!(snapshot.count, (THREADS * PER_THREAD) as u64);
+        assert!(snapshot.min_nanos <= snapshot.max_nanos);
+        assert!(snapshot.max_nanos > 0);
+    }
+
+    #[test]
+    fn welford_mean_stddev_and_sum_match_naive_computation() {
+        let stats = BlockStats::new();
+        let samples = [10_u64, 20, 30, 40, 50];
+
+        for &sample in &samples {
+            stats.record(sample, false);
+        }
+
+        let snapshot = stats.snapshot();
+
+        let naive_sum: u64 = samples.iter().sum();
+        let naive_mean = naive_sum as f64 / samples.len() as f64;
+        let naive_variance = samples
+            .iter()
+            .map(|&x| (x as f64 - naive_mean).powi(2))
+            .sum::<f64>()
+            / samples.len() as f64;
+
+        assert_eq!(snapshot.count, samples.len() as u64);
+        assert_eq!(snapshot.sum_nanos, naive_sum);
+        assert!((snapshot.mean - naive_mean).abs() < 1e-9);
+        assert!((snapshot.stddev - naive_variance.sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn escape_prometheus_label_escapes_special_characters() {
+        let escaped = escape_prometheus_label("foo \"bar\"\\baz\nqux");
+        assert_eq!(escaped, "foo \\\"bar\\\"\\\\baz\\nqux");
     }
 }